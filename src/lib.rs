@@ -1,17 +1,22 @@
 use libc::{c_char, c_void};
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::ffi::{CStr, CString};
+use std::ffi::{CStr, CString, NulError};
+use std::fmt;
 use std::marker::PhantomData;
+use std::ops::Deref;
 use std::ptr;
+use std::ptr::NonNull;
 use std::sync::Once;
 
 #[link(name = "keybinder-3.0")]
 extern "C" {
     fn keybinder_init();
-    fn keybinder_bind(
+    fn keybinder_bind_full(
         keystring: *const c_char,
         handler: unsafe extern "C" fn(*const c_char, *mut c_void),
         user_data: *mut c_void,
+        notify: Option<unsafe extern "C" fn(*mut c_void)>,
     ) -> bool;
     fn keybinder_get_current_event_time() -> u32;
     fn keybinder_set_use_cooked_accelerators(use_cooked: bool);
@@ -21,8 +26,117 @@ extern "C" {
 
 static INIT: Once = Once::new();
 
+thread_local! {
+    /// Table of which `*mut c_void` payload currently owns each bound keystring.
+    ///
+    /// `keybinder_unbind_all` is global, but each `KeyBinder<T>` only tracks
+    /// the keystrings *it* bound in `data_ptrs`. Without this table, two
+    /// `KeyBinder` instances binding the same keystring would silently step
+    /// on each other: the second `bind` would unbind (and the first
+    /// instance's `Drop` would later try to unbind again) a keystring it
+    /// never actually owned. Every `bind`/`unbind`/`Drop` consults this
+    /// registry so only the instance that actually owns a keystring can
+    /// unbind it.
+    ///
+    /// This is thread-local rather than a global `Mutex` because keybinder
+    /// itself is only safe to drive from the thread running the GTK main
+    /// loop; a single process is never expected to bind keystrings from more
+    /// than one thread.
+    static REGISTRY: RefCell<HashMap<String, *mut c_void>> = RefCell::new(HashMap::new());
+}
+
+/// Error returned by [`KeyBinder::new`], [`KeyBinder::bind`],
+/// [`KeyBinder::bind_shared`] and [`KeyBinder::unbind`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeybinderError {
+    /// `keybinder_supported()` returned `false`: keybinder isn't usable on
+    /// this display, or GTK hasn't been initialized yet.
+    Unsupported,
+    /// `keystring` contains an interior NUL byte, so it can't be converted
+    /// to a C string at all.
+    InvalidKeystring(NulError),
+    /// `keystring` is already bound by another `KeyBinder` instance. (A
+    /// keystring this instance already bound is unbound and rebound instead.)
+    AlreadyBound,
+    /// `keybinder_bind_full` itself failed: `keystring` could not be parsed
+    /// as an accelerator, or the underlying X grab failed.
+    BindFailed,
+}
+
+impl fmt::Display for KeybinderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeybinderError::Unsupported => {
+                write!(f, "keybinder is not supported on this display")
+            }
+            KeybinderError::InvalidKeystring(err) => {
+                write!(f, "keystring is not a valid C string: {err}")
+            }
+            KeybinderError::AlreadyBound => {
+                write!(f, "keystring is already bound")
+            }
+            KeybinderError::BindFailed => {
+                write!(f, "keybinder failed to bind keystring")
+            }
+        }
+    }
+}
+
+impl std::error::Error for KeybinderError {}
+
+/// Turns `keystring` into a `CString`, mapping an interior NUL byte to
+/// [`KeybinderError::InvalidKeystring`] instead of panicking.
+fn to_c_keystring(keystring: &str) -> Result<CString, KeybinderError> {
+    CString::new(keystring).map_err(KeybinderError::InvalidKeystring)
+}
+
+/// Hands a freshly `Box::leak`'d payload over to keybinder and, on success,
+/// records it as this instance's in both the thread-local registry and
+/// `data_ptrs`.
+///
+/// Shared by `bind` and `bind_shared` so a fix to the leak-on-failed-grab path
+/// (or to the registry bookkeeping) can't land in one and lag behind in the
+/// other: if `keybinder_bind_full` rejects the binding, keybinder never took
+/// ownership of `payload_ptr`, so it never fires `destroy`; this calls
+/// `destroy` itself to avoid leaking the payload.
+///
+/// # Safety
+///
+/// `payload_ptr` must be a pointer obtained from `Box::leak`, `handler` and
+/// `destroy` must be the matching trampoline pair for the payload's concrete
+/// type, and `destroy` must be safe to call on `payload_ptr` exactly once if
+/// `keybinder_bind_full` fails.
+unsafe fn register_binding(
+    keystring: &str,
+    c_keystring: &CStr,
+    payload_ptr: *mut c_void,
+    handler: unsafe extern "C" fn(*const c_char, *mut c_void),
+    destroy: unsafe extern "C" fn(*mut c_void),
+    data_ptrs: &mut HashMap<String, *mut c_void>,
+) -> Result<(), KeybinderError> {
+    let bound = keybinder_bind_full(c_keystring.as_ptr(), handler, payload_ptr, Some(destroy));
+
+    if !bound {
+        destroy(payload_ptr);
+        return Err(KeybinderError::BindFailed);
+    }
+
+    REGISTRY.with(|registry| {
+        registry
+            .borrow_mut()
+            .insert(keystring.to_string(), payload_ptr)
+    });
+    data_ptrs.insert(keystring.to_string(), payload_ptr);
+
+    Ok(())
+}
+
+/// Boxed user-supplied callback invoked with the bound keystring and the data
+/// it was registered with.
+type Handler<T> = Box<dyn FnMut(String, &T)>;
+
 struct Payload<T> {
-    user_handler: fn(String, &T),
+    user_handler: Handler<T>,
     user_data: T,
 }
 
@@ -37,6 +151,113 @@ unsafe extern "C" fn handler_impl<T>(c_keystring: *const c_char, data: *mut c_vo
     (payload.user_handler)(keystring.to_string(), &payload.user_data)
 }
 
+/// `GDestroyNotify` passed to `keybinder_bind_full`.
+///
+/// keybinder calls this exactly once, when the binding is removed (either by
+/// us via `keybinder_unbind_all` or by keybinder itself if it replaces the
+/// binding), handing ownership of `data` back to us so we can drop it.
+unsafe extern "C" fn destroy_payload<T>(data: *mut c_void) {
+    drop(Box::<Payload<T>>::from_raw(data as *mut Payload<T>));
+}
+
+/// A value that can be shared, via [`ARef`], across several keybindings at once.
+///
+/// Similar to the contract behind `Rc`/`Arc`, except the implementing type
+/// owns its own strong count and storage instead of `std` owning them on its
+/// behalf: `inc_ref` must bump that count, and `dec_ref` must only drop the
+/// value once the count reaches zero.
+pub trait AlwaysRefCounted {
+    /// Increments the strong reference count.
+    fn inc_ref(&self);
+
+    /// Decrements the strong reference count, dropping `obj` once it reaches zero.
+    ///
+    /// # Safety
+    ///
+    /// `obj` must point to a live value previously handed out by an `ARef`
+    /// (i.e. a reference that hasn't been decremented yet).
+    unsafe fn dec_ref(obj: NonNull<Self>);
+}
+
+/// A reference-counted smart pointer to a [`AlwaysRefCounted`] value.
+///
+/// Cloning an `ARef` calls `inc_ref` instead of cloning the pointee, so the
+/// same `T` can be handed to [`KeyBinder::bind_shared`] many times over and is
+/// only dropped once every binding referencing it has been removed.
+pub struct ARef<T: AlwaysRefCounted> {
+    ptr: NonNull<T>,
+    _marker: PhantomData<T>,
+}
+
+impl<T: AlwaysRefCounted> ARef<T> {
+    /// Takes ownership of a reference that has already been incremented.
+    ///
+    /// # Safety
+    ///
+    /// The caller must own a reference to `*ptr` (e.g. by having just called
+    /// `inc_ref`, or by handing over a freshly-created value with a strong
+    /// count of 1) that it is transferring to the returned `ARef`.
+    pub unsafe fn from_raw(ptr: NonNull<T>) -> Self {
+        Self {
+            ptr,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: AlwaysRefCounted> Clone for ARef<T> {
+    fn clone(&self) -> Self {
+        self.inc_ref();
+        Self {
+            ptr: self.ptr,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: AlwaysRefCounted> Deref for ARef<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: holding an `ARef` guarantees the pointee is alive.
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T: AlwaysRefCounted> Drop for ARef<T> {
+    fn drop(&mut self) {
+        // SAFETY: `self.ptr` is the reference this `ARef` owns.
+        unsafe { T::dec_ref(self.ptr) }
+    }
+}
+
+struct SharedPayload<T: AlwaysRefCounted> {
+    user_handler: Handler<T>,
+    user_data: ARef<T>,
+}
+
+/// # Safety:
+///
+/// Same contract as `handler_impl`: `data` is a live `SharedPayload<T>` until
+/// `destroy_shared_payload::<T>` runs.
+unsafe extern "C" fn handler_impl_shared<T: AlwaysRefCounted>(
+    c_keystring: *const c_char,
+    data: *mut c_void,
+) {
+    let keystring = CStr::from_ptr(c_keystring).to_str().unwrap();
+    let payload = ptr::NonNull::new(data as *mut SharedPayload<T>)
+        .unwrap()
+        .as_mut();
+
+    (payload.user_handler)(keystring.to_string(), &payload.user_data)
+}
+
+unsafe extern "C" fn destroy_shared_payload<T: AlwaysRefCounted>(data: *mut c_void) {
+    drop(Box::<SharedPayload<T>>::from_raw(
+        data as *mut SharedPayload<T>,
+    ));
+}
+
 /// # Main Keybinder struct
 ///
 /// This struct is a safe wrapper for KeyBinder and contains functions to
@@ -61,10 +282,10 @@ unsafe extern "C" fn handler_impl<T>(c_keystring: *const c_char, data: *mut c_vo
 ///     let data = String::from("some data");
 ///     let mut keybinder = KeyBinder::<String>::new(true).expect("Keybinder is not supported");
 ///
-///     assert_eq!(keybinder.bind("<Shift>space", |key, data| {
+///     assert!(keybinder.bind("<Shift>space", |key, data| {
 ///         println!("key: {} , data: {}", key, data);
 ///         gtk::main_quit();
-///     }, data), true);
+///     }, data).is_ok());
 ///     println!("Successfully bound keystring to handler");
 ///     gtk::main();
 /// }
@@ -80,10 +301,10 @@ impl<T> KeyBinder<T> {
     /// Creates and initializes Keybinder(It it's not already initialized).
     ///
     /// # Returns
-    /// `Ok(Self)` if KeyBinder is supported. Otherwise, `Err(())`.
-    pub fn new(use_cooked: bool) -> Result<Self, ()> {
+    /// `Ok(Self)` if KeyBinder is supported. Otherwise, `Err(KeybinderError::Unsupported)`.
+    pub fn new(use_cooked: bool) -> Result<Self, KeybinderError> {
         if !unsafe { keybinder_supported() } {
-            return Err(());
+            return Err(KeybinderError::Unsupported);
         }
 
         INIT.call_once(|| unsafe { keybinder_init() });
@@ -100,52 +321,135 @@ impl<T> KeyBinder<T> {
 
     /// Binds handler to given keystring and passes the user data to handler
     /// when key is pressed.
-    pub fn bind(&mut self, keystring: &str, user_handler: fn(String, &T), user_data: T) -> bool {
-        // To make sure the keystring is not already bound.
+    ///
+    /// Unlike a bare function pointer, `user_handler` may capture its environment,
+    /// so callers can close over state (a counter, a channel sender, a widget
+    /// handle) instead of smuggling everything through `user_data`.
+    pub fn bind<F: FnMut(String, &T) + 'static>(
+        &mut self,
+        keystring: &str,
+        user_handler: F,
+        user_data: T,
+    ) -> Result<(), KeybinderError> {
+        // To make sure the keystring is not already bound by us.
         // It'll not do anything if the keystring isn't bound.
-        self.unbind(keystring);
+        self.unbind(keystring).ok();
+
+        if REGISTRY.with(|registry| registry.borrow().contains_key(keystring)) {
+            return Err(KeybinderError::AlreadyBound);
+        }
 
-        let c_keystring = CString::new(keystring).unwrap();
+        let c_keystring = to_c_keystring(keystring)?;
 
         // Put the data in heap and immediately leak it so that when it's passed to
         // handler, it's valid. If we don't leak it, the data will drop after this scope ends.
         // This would result in use after free.
         let payload_ptr = Box::leak(Box::new(Payload {
             user_data,
-            user_handler,
+            user_handler: Box::new(user_handler),
         })) as *const _ as *mut c_void;
 
-        self.data_ptrs.insert(keystring.to_string(), payload_ptr);
-
-        // Handler properly handles the data and payload_ptr is valid unless the keystring is unbinded.
-        // To prevent use after free, the drop implementation unbinds the keystring and frees the data_ptr.
-        unsafe { keybinder_bind(c_keystring.as_ptr(), handler_impl::<T>, payload_ptr) }
+        // keybinder now owns payload_ptr: it calls destroy_payload::<T> exactly
+        // once, when the binding is removed, which reconstructs and drops the
+        // Box for us. We never free it ourselves. If the grab fails,
+        // register_binding frees payload_ptr itself.
+        unsafe {
+            register_binding(
+                keystring,
+                &c_keystring,
+                payload_ptr,
+                handler_impl::<T>,
+                destroy_payload::<T>,
+                &mut self.data_ptrs,
+            )
+        }
     }
 
-    /// Unbinds the given keystring. If it's not bound, it does nothing.
-    pub fn unbind(&mut self, keystring: &str) {
-        if self.data_ptrs.contains_key(keystring) {
-            // SAFETY: Two `keystring` can't have the save data_ptr. This prevents double free.
-            //         Also, the data is alloc'd by KeyBinder::bind() and is never dealloc'd unless
-            //         the user unbinds it. In that case, KeyBinder::unbind() removes the data_ptr from
-            //         the hashmap.
-            unsafe {
-                Self::unbind_impl(keystring, *self.data_ptrs.get(keystring).unwrap());
-            }
+    /// Binds `user_handler` to `keystring`, backed by a reference-counted
+    /// `user_data` instead of a value owned solely by this binding.
+    ///
+    /// Unlike [`KeyBinder::bind`], `user_data` can be cloned (cheaply, via
+    /// `ARef::clone`) and passed to several `bind_shared` calls so that many
+    /// keystrings route into the same underlying controller object; it's
+    /// only dropped once the last binding referencing it is removed.
+    pub fn bind_shared<F: FnMut(String, &T) + 'static>(
+        &mut self,
+        keystring: &str,
+        user_handler: F,
+        user_data: ARef<T>,
+    ) -> Result<(), KeybinderError>
+    where
+        T: AlwaysRefCounted,
+    {
+        // To make sure the keystring is not already bound by us.
+        // It'll not do anything if the keystring isn't bound.
+        self.unbind(keystring).ok();
+
+        if REGISTRY.with(|registry| registry.borrow().contains_key(keystring)) {
+            return Err(KeybinderError::AlreadyBound);
+        }
+
+        let c_keystring = to_c_keystring(keystring)?;
+
+        let payload_ptr = Box::leak(Box::new(SharedPayload {
+            user_data,
+            user_handler: Box::new(user_handler),
+        })) as *const _ as *mut c_void;
 
-            self.data_ptrs.remove(keystring).unwrap();
+        // keybinder now owns payload_ptr: it calls destroy_shared_payload::<T>
+        // exactly once, when the binding is removed, which drops the Box (and,
+        // via ARef's Drop, decrements user_data's reference count) for us. If
+        // the grab fails, register_binding frees payload_ptr itself.
+        unsafe {
+            register_binding(
+                keystring,
+                &c_keystring,
+                payload_ptr,
+                handler_impl_shared::<T>,
+                destroy_shared_payload::<T>,
+                &mut self.data_ptrs,
+            )
+        }
+    }
+
+    /// Unbinds the given keystring. If this instance doesn't own it (it's not
+    /// bound, or it's bound by a different `KeyBinder`), it does nothing.
+    pub fn unbind(&mut self, keystring: &str) -> Result<(), KeybinderError> {
+        if let Some(payload_ptr) = self.data_ptrs.remove(keystring) {
+            // SAFETY: keystring was bound by this instance via keybinder_bind_full,
+            // so unbinding it triggers its destroy callback (destroy_payload::<T>
+            // or destroy_shared_payload::<T>) exactly once.
+            unsafe { Self::unbind_impl(keystring, payload_ptr) }
+        } else {
+            Ok(())
         }
     }
 
     /// # Safety:
-    /// Caller has to make sure that data isn't freed twice and the data_ptr is valid
-    unsafe fn unbind_impl(keystring: &str, data_ptr: *mut c_void) {
-        let c_keystring = CString::new(keystring).unwrap();
-        
-        // TODO: check if it's still leaking or not
-        let _ = Box::<Payload<T>>::from_raw(data_ptr as *mut Payload<T>);
+    /// Caller has to make sure `keystring` was actually bound by this instance
+    /// with the payload at `payload_ptr`, so that keybinder has a payload
+    /// registered for it and the matching destroy callback gets invoked.
+    unsafe fn unbind_impl(keystring: &str, payload_ptr: *mut c_void) -> Result<(), KeybinderError> {
+        // Only remove the registry entry (and tell keybinder to unbind) if we
+        // are still the registered owner: another instance may have since
+        // rebound this keystring, and `keybinder_unbind_all` is global, so
+        // unbinding here would steal it out from under that instance.
+        let owns = REGISTRY.with(|registry| {
+            let mut registry = registry.borrow_mut();
+            if registry.get(keystring) == Some(&payload_ptr) {
+                registry.remove(keystring);
+                true
+            } else {
+                false
+            }
+        });
 
-        keybinder_unbind_all(c_keystring.as_ptr());
+        if owns {
+            let c_keystring = to_c_keystring(keystring)?;
+            keybinder_unbind_all(c_keystring.as_ptr());
+        }
+
+        Ok(())
     }
 }
 
@@ -155,14 +459,80 @@ pub fn get_current_event_time() -> u32 {
 
 impl<T> Drop for KeyBinder<T> {
     fn drop(&mut self) {
-        for keystring in self.data_ptrs.keys() {
-            // SAFETY: Two `keystring` can't have the save data_ptr. This prevents double free.
-            //         Also, the data is alloc'd by KeyBinder::bind() and never dealloc'd unless
-            //         the user unbinds it. In that case, KeyBinder::unbind() removes the data_ptr from
-            //         the hashmap.
+        for (keystring, payload_ptr) in self.data_ptrs.iter() {
+            // SAFETY: keystring was bound by this instance via keybinder_bind_full,
+            // so unbinding it triggers its destroy callback (destroy_payload::<T>
+            // or destroy_shared_payload::<T>) exactly once.
+            // keystring was already validated as a C string when it was bound,
+            // so unbind_impl can't fail here.
             unsafe {
-                Self::unbind_impl(keystring, *self.data_ptrs.get(keystring).unwrap());
+                Self::unbind_impl(keystring, *payload_ptr).ok();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// A toy `AlwaysRefCounted` type: tracks its own strong count and flips
+    /// `dropped` to `true` when it's actually freed.
+    struct Counted {
+        strong: Cell<usize>,
+        dropped: Rc<Cell<bool>>,
+    }
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.dropped.set(true);
+        }
+    }
+
+    impl AlwaysRefCounted for Counted {
+        fn inc_ref(&self) {
+            self.strong.set(self.strong.get() + 1);
+        }
+
+        unsafe fn dec_ref(mut obj: NonNull<Self>) {
+            let remaining = obj.as_ref().strong.get() - 1;
+            obj.as_mut().strong.set(remaining);
+            if remaining == 0 {
+                drop(Box::from_raw(obj.as_ptr()));
             }
         }
     }
+
+    #[test]
+    fn aref_clone_increments_and_drop_decrements_the_strong_count() {
+        let dropped = Rc::new(Cell::new(false));
+        let ptr = NonNull::new(Box::into_raw(Box::new(Counted {
+            strong: Cell::new(1),
+            dropped: dropped.clone(),
+        })))
+        .unwrap();
+        let a = unsafe { ARef::from_raw(ptr) };
+
+        assert_eq!(a.strong.get(), 1);
+
+        let b = a.clone();
+        assert_eq!(a.strong.get(), 2);
+        assert_eq!(b.strong.get(), 2);
+        assert!(!dropped.get());
+
+        drop(b);
+        assert_eq!(a.strong.get(), 1);
+        assert!(
+            !dropped.get(),
+            "Counted must not be freed while an ARef still references it"
+        );
+
+        drop(a);
+        assert!(
+            dropped.get(),
+            "Counted must be freed once the last ARef is dropped"
+        );
+    }
 }